@@ -1,125 +1,1422 @@
-use std::collections::HashMap;
-
 // Note:
 // 1. the linked list is FIFO, push to head, pop from the tail
 
+type OrderIdx = u32;
+type LimitIdx = u32;
+
+// Slot-based arena: orders/limits live in a flat Vec and recycled slots are
+// threaded through a free list, so steady-state add/cancel traffic does no
+// heap allocation and no hashing (unlike a HashMap-per-id).
+enum Slot<T> {
+  Occupied(T),
+  Vacant(Option<u32>),
+}
+
+struct Arena<T> {
+  slots: Vec<Slot<T>>,
+  free_head: Option<u32>,
+}
+
+impl<T> Arena<T> {
+  fn with_capacity(capacity: usize) -> Arena<T> {
+    Arena { slots: Vec::with_capacity(capacity), free_head: None }
+  }
+
+  fn alloc(&mut self, value: T) -> u32 {
+    match self.free_head {
+      Some(idx) => {
+        let next_free = match &self.slots[idx as usize] {
+          Slot::Vacant(next) => *next,
+          Slot::Occupied(_) => unreachable!("free list pointed at an occupied slot"),
+        };
+        self.free_head = next_free;
+        self.slots[idx as usize] = Slot::Occupied(value);
+        idx
+      }
+      None => {
+        let idx = self.slots.len() as u32;
+        self.slots.push(Slot::Occupied(value));
+        idx
+      }
+    }
+  }
+
+  // Frees an occupied slot if `idx` is in range and currently occupied; `None` otherwise.
+  fn try_free(&mut self, idx: u32) -> Option<T> {
+    let occupied = matches!(self.slots.get(idx as usize), Some(Slot::Occupied(_)));
+    if occupied { Some(self.free(idx)) } else { None }
+  }
+
+  fn free(&mut self, idx: u32) -> T {
+    let slot = std::mem::replace(&mut self.slots[idx as usize], Slot::Vacant(self.free_head));
+    self.free_head = Some(idx);
+    match slot {
+      Slot::Occupied(value) => value,
+      Slot::Vacant(_) => panic!("double free of arena slot {}", idx),
+    }
+  }
+
+  fn get(&self, idx: u32) -> &T {
+    match &self.slots[idx as usize] {
+      Slot::Occupied(value) => value,
+      Slot::Vacant(_) => panic!("use of freed arena slot {}", idx),
+    }
+  }
+
+  fn get_mut(&mut self, idx: u32) -> &mut T {
+    match &mut self.slots[idx as usize] {
+      Slot::Occupied(value) => value,
+      Slot::Vacant(_) => panic!("use of freed arena slot {}", idx),
+    }
+  }
+}
+
+// What a caller submits to the book. `Order` itself is arena-resident and
+// carries only the bookkeeping the engine needs once the order is admitted.
+struct NewOrder {
+  owner_id: u64,
+  is_buy: bool,
+  shares: u64,
+  limit: u64,
+}
 
 struct Order {
-  order_id: u64,
+  owner_id: u64,
   is_buy: bool,
   shares: u64,
   limit: u64,
-  next_order: Option<u64>,
-  prev_order: Option<u64>,
-  parent_limit: Option<u64>,
+  next_order: Option<OrderIdx>,
+  prev_order: Option<OrderIdx>,
+  parent_limit: Option<LimitIdx>,
+}
+
+// A resting order that tracks a moving reference price instead of quoting an
+// absolute limit. `peg_limit` is the worst effective price the owner will
+// accept (a ceiling for buys, a floor for sells); it is NOT a price the order
+// trades through, so if `oracle_price + peg_offset` would violate it the
+// order is simply not marketable until the oracle moves back in its favor.
+struct NewPegOrder {
+  owner_id: u64,
+  is_buy: bool,
+  shares: u64,
+  peg_offset: i64,
+  peg_limit: u64,
+}
+
+struct PegOrder {
+  owner_id: u64,
+  is_buy: bool,
+  shares: u64,
+  peg_offset: i64,
+  peg_limit: u64,
+  next_order: Option<OrderIdx>,
+  prev_order: Option<OrderIdx>,
+  parent_limit: Option<LimitIdx>,
+}
+
+// Governs what happens when an incoming order would cross against a resting
+// order owned by the same account.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SelfTradeBehavior {
+  // Shrink both the resting order and the remaining incoming quantity by the
+  // overlapping size instead of trading them against each other.
+  DecrementTake,
+  // Pull the resting order out of the book without a trade, then keep matching deeper.
+  CancelProvide,
+  // Refuse to touch the book at all if a self-trade would occur anywhere in the fill.
+  AbortTransaction,
+}
+
+#[derive(Debug)]
+enum OrderBookError {
+  SelfTradeAborted,
+  InvalidTickSize { limit: u64, tick_size: u64 },
+  InvalidLotSize { shares: u64, lot_size: u64 },
+  BelowMinSize { shares: u64, min_size: u64 },
+}
+
+// Fixed orders and peg orders live in separate arenas, both indexed from 0,
+// so a bare order id can't tell a consumer which one it came from. Events
+// carry this alongside maker/cancelled ids to disambiguate.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum OrderKind {
+  Fixed,
+  Peg,
+}
+
+// Structured record of what the matching engine did, so a consumer can
+// process fills for settlement/accounting without hooking into the hot path.
+#[derive(Debug)]
+enum Event {
+  Fill { maker_order_id: u64, maker_kind: OrderKind, taker_order_id: u64, price: u64, shares: u64, maker_side: bool },
+  Out { order_id: u64, kind: OrderKind },
 }
 
+type EventQueue = Vec<Event>;
+
 struct Limit {
   limit_price: u64,
   size: u64,
   total_vol: u64,
-  parent: u64,
+  parent: Option<LimitIdx>,
+  order_count: u64,
+  left_child: Option<LimitIdx>,
+  right_child: Option<LimitIdx>,
+  head_order: Option<OrderIdx>,
+  tail_order: Option<OrderIdx>,
+}
+
+// Peg levels are keyed by `peg_offset` rather than price: for a fixed
+// `oracle_price`, effective price is a monotonic function of offset, so
+// ordering by offset is equivalent to ordering by effective price. There is
+// no `total_vol`, since the price each resting share trades at floats with
+// the oracle instead of being fixed per level.
+struct PegLevel {
+  peg_offset: i64,
+  size: u64,
+  parent: Option<LimitIdx>,
   order_count: u64,
-  left_child: Option<u64>,
-  right_child: Option<u64>,
-  head_order: Option<u64>,
-  tail_order: Option<u64>,
+  left_child: Option<LimitIdx>,
+  right_child: Option<LimitIdx>,
+  head_order: Option<OrderIdx>,
+  tail_order: Option<OrderIdx>,
 }
 
 struct OrderBook {
-  orders_ownership_map: HashMap<u64, Order>,
-  limits_ownership_map: HashMap<u64, Limit>,
-  limits_lookup_map: HashMap<u64, u64>,
-  buy_tree_root: Option<u64>,
-  sell_tree_root: Option<u64>,
+  orders: Arena<Order>,
+  limits: Arena<Limit>,
+  // price -> limit index, split per side: prices are bounded small integers
+  // so a plain array beats hashing on every lookup, and a bid and an ask
+  // can rest at the same price without colliding on one shared slot.
+  buy_limits_lookup: Vec<Option<LimitIdx>>,
+  sell_limits_lookup: Vec<Option<LimitIdx>>,
+  buy_tree_root: Option<LimitIdx>,
+  sell_tree_root: Option<LimitIdx>,
+  // cached inside of the book: highest buy price / lowest sell price, so the
+  // matching engine never has to walk a tree just to find the touch
+  best_buy_limit: Option<LimitIdx>,
+  best_sell_limit: Option<LimitIdx>,
+  // pegged orders, keyed by offset instead of price (see `PegLevel`)
+  peg_orders: Arena<PegOrder>,
+  peg_levels: Arena<PegLevel>,
+  peg_buy_tree_root: Option<LimitIdx>,
+  peg_sell_tree_root: Option<LimitIdx>,
+  // highest-offset buy peg / lowest-offset sell peg; stable regardless of
+  // `oracle_price` since it only reorders effective price, not offset order
+  best_peg_buy_level: Option<LimitIdx>,
+  best_peg_sell_level: Option<LimitIdx>,
+  oracle_price: u64,
+  events: EventQueue,
+  // market parameters enforced on every incoming order
+  tick_size: u64,
+  lot_size: u64,
+  min_size: u64,
 }
 
-fn add_order(ob: &mut OrderBook, mut order: Order) {
-  let order_id = order.order_id;
+impl OrderBook {
+  // `max_price` sizes the price->limit lookup table up front; `capacity` is
+  // the expected number of live orders/limits, preallocated in the arenas.
+  fn new(max_price: u64, capacity: usize, tick_size: u64, lot_size: u64, min_size: u64) -> OrderBook {
+    OrderBook {
+      orders: Arena::with_capacity(capacity),
+      limits: Arena::with_capacity(capacity),
+      buy_limits_lookup: vec![None; max_price as usize + 1],
+      sell_limits_lookup: vec![None; max_price as usize + 1],
+      buy_tree_root: None,
+      sell_tree_root: None,
+      best_buy_limit: None,
+      best_sell_limit: None,
+      peg_orders: Arena::with_capacity(capacity),
+      peg_levels: Arena::with_capacity(capacity),
+      peg_buy_tree_root: None,
+      peg_sell_tree_root: None,
+      best_peg_buy_level: None,
+      best_peg_sell_level: None,
+      oracle_price: 0,
+      events: Vec::new(),
+      tick_size,
+      lot_size,
+      min_size,
+    }
+  }
 
-  let limit_id_opt = ob.limits_lookup_map.get(&order.limit).copied();
-  if limit_id_opt == None {
-    // No limit node, create one
+  // Hands the caller every event recorded since the last drain.
+  fn drain_events(&mut self) -> Vec<Event> {
+    std::mem::take(&mut self.events)
+  }
 
-    add_to_tree();
+  // Updates the reference price pegged orders track. Effective prices are
+  // always computed live off this field during matching, so there is
+  // nothing to recompute eagerly here.
+  fn set_oracle_price(&mut self, oracle_price: u64) {
+    self.oracle_price = oracle_price;
+  }
+}
 
-    return
+// Effective price of a peg order at the current oracle price, or `None` if
+// it would violate the order's own `peg_limit` cap (buys cap the price they
+// will pay, sells cap the price they will accept) and is therefore
+// temporarily unmarketable rather than tradeable-at-the-cap.
+fn peg_effective_price(oracle_price: u64, peg_offset: i64, peg_limit: u64, is_buy: bool) -> Option<u64> {
+  let raw = oracle_price as i64 + peg_offset;
+  if raw < 0 {
+    return None;
+  }
+  let raw = raw as u64;
+  if is_buy {
+    if raw > peg_limit { None } else { Some(raw) }
+  } else if raw < peg_limit {
+    None
+  } else {
+    Some(raw)
+  }
+}
+
+// Picks the price->limit lookup table for one side of the book.
+fn limits_lookup(ob: &OrderBook, is_buy: bool) -> &Vec<Option<LimitIdx>> {
+  if is_buy { &ob.buy_limits_lookup } else { &ob.sell_limits_lookup }
+}
+
+fn limits_lookup_mut(ob: &mut OrderBook, is_buy: bool) -> &mut Vec<Option<LimitIdx>> {
+  if is_buy { &mut ob.buy_limits_lookup } else { &mut ob.sell_limits_lookup }
+}
+
+// Rejects prices/sizes that don't respect the book's tick/lot/min-size granularity.
+fn validate_order(ob: &OrderBook, shares: u64, limit: u64) -> Result<(), OrderBookError> {
+  if limit % ob.tick_size != 0 {
+    return Err(OrderBookError::InvalidTickSize { limit, tick_size: ob.tick_size });
+  }
+  if shares % ob.lot_size != 0 {
+    return Err(OrderBookError::InvalidLotSize { shares, lot_size: ob.lot_size });
+  }
+  if shares < ob.min_size {
+    return Err(OrderBookError::BelowMinSize { shares, min_size: ob.min_size });
   }
+  Ok(())
+}
+
+fn add_order(ob: &mut OrderBook, input: NewOrder) -> Result<(), OrderBookError> {
+  validate_order(ob, input.shares, input.limit)?;
+  rest_order(ob, input);
+  Ok(())
+}
+
+// Inserts `input` into the book without validation. Used directly by
+// `add_order` (after validating) and by the execute paths to rest an
+// already-matched-against remainder: that remainder's price came from the
+// (already validated) taker price, but its size may fall below `min_size`
+// or off a `lot_size` multiple, and rejecting it there would return an
+// error after fills have already been emitted against a now-mutated book.
+fn rest_order(ob: &mut OrderBook, input: NewOrder) {
+  let price = input.limit;
+  let is_buy = input.is_buy;
+
+  let limit_id = match limits_lookup(ob, is_buy)[price as usize] {
+    Some(limit_id) => limit_id,
+    None => add_to_tree(ob, price, is_buy),
+  };
 
   // Existing limit node, append to the list
-  let limit_id = limit_id_opt.unwrap();
-  let limit = ob.limits_ownership_map.get_mut(&limit_id).unwrap();
+  let head_order = ob.limits.get(limit_id).head_order;
 
-  order.parent_limit = Some(limit_id);
-  order.next_order = limit.head_order.clone();
-  order.prev_order = None;
+  let order = Order {
+    owner_id: input.owner_id,
+    is_buy,
+    shares: input.shares,
+    limit: price,
+    next_order: head_order,
+    prev_order: None,
+    parent_limit: Some(limit_id),
+  };
+  let shares = order.shares;
+  let order_id = ob.orders.alloc(order);
 
-  if limit.head_order != None {
-    let order_id = limit.head_order.unwrap();
-    let head_order = ob.orders_ownership_map.get_mut(&order_id).unwrap();
-    head_order.prev_order = Some(order.order_id);
-  } else {
-    limit.tail_order = Some(order_id);
+  match head_order {
+    Some(old_head_id) => {
+      ob.orders.get_mut(old_head_id).prev_order = Some(order_id);
+    }
+    None => {
+      ob.limits.get_mut(limit_id).tail_order = Some(order_id);
+    }
   }
 
+  let limit = ob.limits.get_mut(limit_id);
   limit.head_order = Some(order_id);
   limit.order_count += 1;
-  limit.size += order.shares;
-  limit.total_vol += order.shares * limit.limit_price;
-
-  ob.orders_ownership_map.insert(order_id, order);
-}
-
-fn drain_limit(shares_to_execute: u64, best_sell_limit: &mut Limit) -> (u64, bool) {
-  // 1. while limit->tail_order != NULL and shares_to_execute > 0:
-  // 1.1 let curr_shares = limit->tail_order->shares
-  // 1.2 if curr_shares > shares_to_execute:
-  // 1.2.1 limit->tail_order->shares = curr_shares - shares_to_execute
-  // 1.2.2 shares_to_execute = 0 and break
-  // 1.3 else:
-  // 1.3.1 limit->tail_order = limit->tail_order->prev
-  // 1.3.2 shares_to_execute -= limit->tail_order->shares
-  // 1.3.3 remove tail_order and remove this order from hash map
-  // 1.3.4 handle the head pointer edge cases when we approach one-node list (don't consider empty list because when it's empty we kick this limit out)
-  // 1.4 if the limit's linked list is empty now, signal delete: delete this limit from tree and hash map (we don't delete immediately because we want to use it to find the predecessor in the parent function)
-  // return shares_to_execute, delete
-}
-
-fn execute_buy_order(shares_to_execute: u64, expected_price: u64) {
-  // 1. while shares_to_execute > 0 and best_sell_price is not NULL we do:
-  // 1.1 Get the best sell Limit price best_sell_price (we keep track of this Limit object in the OrderBook struct so O(1)). Note this is always the smallest price in the sell tree, which is physically the leftmost node.
-  // 1.2 If best_sell_price > expected price: break
-  // 1.3 Execute as much as we can, call drain_limit(), update shares_to_execute
-  // 1.4 If signalled to delete:
-  // 1.4.1 Find its largest predecessor, set as the best_sell_price in OrderBook struct, delete the Limit
-  // 1.5 If shares_to_execute == 0, means execution is done, return
-  // 1.6 If shares_to_execute > 0, means we need to add this one to the buy tree, add the new Order at the Limit price, call add_order()
+  limit.size += shares;
+  limit.total_vol += shares * limit.limit_price;
+
+  update_best_limit_on_insert(ob, limit_id, price, is_buy);
+}
+
+// Creates a fresh Limit at `price`, inserts it into the buy/sell price tree,
+// and returns its id. Assumes `price` is not already present in the tree.
+fn add_to_tree(ob: &mut OrderBook, price: u64, is_buy: bool) -> LimitIdx {
+  let new_limit = Limit {
+    limit_price: price,
+    size: 0,
+    total_vol: 0,
+    parent: None,
+    order_count: 0,
+    left_child: None,
+    right_child: None,
+    head_order: None,
+    tail_order: None,
+  };
+  let limit_id = ob.limits.alloc(new_limit);
+
+  let root = if is_buy { ob.buy_tree_root } else { ob.sell_tree_root };
+  match root {
+    None => {
+      if is_buy {
+        ob.buy_tree_root = Some(limit_id);
+      } else {
+        ob.sell_tree_root = Some(limit_id);
+      }
+    }
+    Some(root_id) => {
+      let mut cur_id = root_id;
+      loop {
+        let cur = ob.limits.get(cur_id);
+        let go_left = price < cur.limit_price;
+        let next_id = if go_left { cur.left_child } else { cur.right_child };
+        match next_id {
+          Some(id) => cur_id = id,
+          None => {
+            ob.limits.get_mut(limit_id).parent = Some(cur_id);
+            let cur = ob.limits.get_mut(cur_id);
+            if go_left {
+              cur.left_child = Some(limit_id);
+            } else {
+              cur.right_child = Some(limit_id);
+            }
+            break;
+          }
+        }
+      }
+    }
+  }
+
+  limits_lookup_mut(ob, is_buy)[price as usize] = Some(limit_id);
+  limit_id
+}
+
+fn update_best_limit_on_insert(ob: &mut OrderBook, limit_id: LimitIdx, price: u64, is_buy: bool) {
+  if is_buy {
+    let better = match ob.best_buy_limit {
+      None => true,
+      Some(id) => price > ob.limits.get(id).limit_price,
+    };
+    if better {
+      ob.best_buy_limit = Some(limit_id);
+    }
+  } else {
+    let better = match ob.best_sell_limit {
+      None => true,
+      Some(id) => price < ob.limits.get(id).limit_price,
+    };
+    if better {
+      ob.best_sell_limit = Some(limit_id);
+    }
+  }
+}
+
+fn add_peg_order(ob: &mut OrderBook, input: NewPegOrder) -> Result<(), OrderBookError> {
+  if input.shares % ob.lot_size != 0 {
+    return Err(OrderBookError::InvalidLotSize { shares: input.shares, lot_size: ob.lot_size });
+  }
+  if input.shares < ob.min_size {
+    return Err(OrderBookError::BelowMinSize { shares: input.shares, min_size: ob.min_size });
+  }
+
+  let is_buy = input.is_buy;
+  let root = if is_buy { ob.peg_buy_tree_root } else { ob.peg_sell_tree_root };
+  let level_id = find_peg_level(ob, input.peg_offset, root).unwrap_or_else(|| add_peg_to_tree(ob, input.peg_offset, is_buy));
+
+  let head_order = ob.peg_levels.get(level_id).head_order;
+
+  let order = PegOrder {
+    owner_id: input.owner_id,
+    is_buy,
+    shares: input.shares,
+    peg_offset: input.peg_offset,
+    peg_limit: input.peg_limit,
+    next_order: head_order,
+    prev_order: None,
+    parent_limit: Some(level_id),
+  };
+  let shares = order.shares;
+  let order_id = ob.peg_orders.alloc(order);
+
+  match head_order {
+    Some(old_head_id) => {
+      ob.peg_orders.get_mut(old_head_id).prev_order = Some(order_id);
+    }
+    None => {
+      ob.peg_levels.get_mut(level_id).tail_order = Some(order_id);
+    }
+  }
+
+  let level = ob.peg_levels.get_mut(level_id);
+  level.head_order = Some(order_id);
+  level.order_count += 1;
+  level.size += shares;
+
+  update_best_peg_on_insert(ob, level_id, input.peg_offset, is_buy);
+
+  Ok(())
+}
+
+// Walks `root` looking for a level already keyed at `offset`, since (unlike
+// fixed prices) offsets aren't dense enough to bound with an array lookup.
+fn find_peg_level(ob: &OrderBook, offset: i64, root: Option<LimitIdx>) -> Option<LimitIdx> {
+  let mut cur = root;
+  while let Some(cur_id) = cur {
+    let level = ob.peg_levels.get(cur_id);
+    if offset == level.peg_offset {
+      return Some(cur_id);
+    }
+    cur = if offset < level.peg_offset { level.left_child } else { level.right_child };
+  }
+  None
+}
+
+// Creates a fresh PegLevel at `offset`, inserts it into the buy/sell peg
+// tree, and returns its id. Assumes `offset` is not already present.
+fn add_peg_to_tree(ob: &mut OrderBook, offset: i64, is_buy: bool) -> LimitIdx {
+  let new_level = PegLevel {
+    peg_offset: offset,
+    size: 0,
+    parent: None,
+    order_count: 0,
+    left_child: None,
+    right_child: None,
+    head_order: None,
+    tail_order: None,
+  };
+  let level_id = ob.peg_levels.alloc(new_level);
+
+  let root = if is_buy { ob.peg_buy_tree_root } else { ob.peg_sell_tree_root };
+  match root {
+    None => {
+      if is_buy {
+        ob.peg_buy_tree_root = Some(level_id);
+      } else {
+        ob.peg_sell_tree_root = Some(level_id);
+      }
+    }
+    Some(root_id) => {
+      let mut cur_id = root_id;
+      loop {
+        let cur = ob.peg_levels.get(cur_id);
+        let go_left = offset < cur.peg_offset;
+        let next_id = if go_left { cur.left_child } else { cur.right_child };
+        match next_id {
+          Some(id) => cur_id = id,
+          None => {
+            ob.peg_levels.get_mut(level_id).parent = Some(cur_id);
+            let cur = ob.peg_levels.get_mut(cur_id);
+            if go_left {
+              cur.left_child = Some(level_id);
+            } else {
+              cur.right_child = Some(level_id);
+            }
+            break;
+          }
+        }
+      }
+    }
+  }
+
+  level_id
+}
+
+fn update_best_peg_on_insert(ob: &mut OrderBook, level_id: LimitIdx, offset: i64, is_buy: bool) {
+  if is_buy {
+    let better = match ob.best_peg_buy_level {
+      None => true,
+      Some(id) => offset > ob.peg_levels.get(id).peg_offset,
+    };
+    if better {
+      ob.best_peg_buy_level = Some(level_id);
+    }
+  } else {
+    let better = match ob.best_peg_sell_level {
+      None => true,
+      Some(id) => offset < ob.peg_levels.get(id).peg_offset,
+    };
+    if better {
+      ob.best_peg_sell_level = Some(level_id);
+    }
+  }
+}
+
+// In-order successor: the next higher-offset PegLevel in the tree.
+fn find_peg_successor(ob: &OrderBook, level_id: LimitIdx) -> Option<LimitIdx> {
+  let level = ob.peg_levels.get(level_id);
+  if let Some(right_id) = level.right_child {
+    let mut cur = right_id;
+    while let Some(left_id) = ob.peg_levels.get(cur).left_child {
+      cur = left_id;
+    }
+    return Some(cur);
+  }
+  let mut child = level_id;
+  let mut parent = level.parent;
+  while let Some(parent_id) = parent {
+    let parent_level = ob.peg_levels.get(parent_id);
+    if parent_level.left_child == Some(child) {
+      return Some(parent_id);
+    }
+    child = parent_id;
+    parent = parent_level.parent;
+  }
+  None
+}
+
+// In-order predecessor: the next lower-offset PegLevel in the tree.
+fn find_peg_predecessor(ob: &OrderBook, level_id: LimitIdx) -> Option<LimitIdx> {
+  let level = ob.peg_levels.get(level_id);
+  if let Some(left_id) = level.left_child {
+    let mut cur = left_id;
+    while let Some(right_id) = ob.peg_levels.get(cur).right_child {
+      cur = right_id;
+    }
+    return Some(cur);
+  }
+  let mut child = level_id;
+  let mut parent = level.parent;
+  while let Some(parent_id) = parent {
+    let parent_level = ob.peg_levels.get(parent_id);
+    if parent_level.right_child == Some(child) {
+      return Some(parent_id);
+    }
+    child = parent_id;
+    parent = parent_level.parent;
+  }
+  None
+}
+
+// In-order successor: the next higher-priced Limit in the tree.
+fn find_successor(ob: &OrderBook, limit_id: LimitIdx) -> Option<LimitIdx> {
+  let limit = ob.limits.get(limit_id);
+  if let Some(right_id) = limit.right_child {
+    let mut cur = right_id;
+    while let Some(left_id) = ob.limits.get(cur).left_child {
+      cur = left_id;
+    }
+    return Some(cur);
+  }
+  let mut child = limit_id;
+  let mut parent = limit.parent;
+  while let Some(parent_id) = parent {
+    let parent_limit = ob.limits.get(parent_id);
+    if parent_limit.left_child == Some(child) {
+      return Some(parent_id);
+    }
+    child = parent_id;
+    parent = parent_limit.parent;
+  }
+  None
+}
+
+// In-order predecessor: the next lower-priced Limit in the tree.
+fn find_predecessor(ob: &OrderBook, limit_id: LimitIdx) -> Option<LimitIdx> {
+  let limit = ob.limits.get(limit_id);
+  if let Some(left_id) = limit.left_child {
+    let mut cur = left_id;
+    while let Some(right_id) = ob.limits.get(cur).right_child {
+      cur = right_id;
+    }
+    return Some(cur);
+  }
+  let mut child = limit_id;
+  let mut parent = limit.parent;
+  while let Some(parent_id) = parent {
+    let parent_limit = ob.limits.get(parent_id);
+    if parent_limit.right_child == Some(child) {
+      return Some(parent_id);
+    }
+    child = parent_id;
+    parent = parent_limit.parent;
+  }
+  None
+}
+
+fn cancel_order(ob: &mut OrderBook, order_id: OrderIdx) -> bool {
+  let order = match ob.orders.try_free(order_id) {
+    Some(order) => order,
+    None => return false,
+  };
+
+  let limit_id = order.parent_limit.unwrap();
+  let is_buy = order.is_buy;
+
+  ob.events.push(Event::Out { order_id: order_id as u64, kind: OrderKind::Fixed });
+
+  // splice the order out of its limit's FIFO list
+  match order.prev_order {
+    Some(prev_id) => {
+      ob.orders.get_mut(prev_id).next_order = order.next_order;
+    }
+    None => {
+      ob.limits.get_mut(limit_id).head_order = order.next_order;
+    }
+  }
+  match order.next_order {
+    Some(next_id) => {
+      ob.orders.get_mut(next_id).prev_order = order.prev_order;
+    }
+    None => {
+      ob.limits.get_mut(limit_id).tail_order = order.prev_order;
+    }
+  }
+
+  let limit = ob.limits.get_mut(limit_id);
+  limit.order_count -= 1;
+  limit.size -= order.shares;
+  limit.total_vol -= order.shares * limit.limit_price;
+
+  if limit.order_count == 0 {
+    let is_best = if is_buy {
+      ob.best_buy_limit == Some(limit_id)
+    } else {
+      ob.best_sell_limit == Some(limit_id)
+    };
+    let replacement = if is_best {
+      if is_buy { find_predecessor(ob, limit_id) } else { find_successor(ob, limit_id) }
+    } else {
+      None
+    };
+
+    remove_limit(ob, limit_id, is_buy);
+
+    if is_best {
+      if is_buy {
+        ob.best_buy_limit = replacement;
+      } else {
+        ob.best_sell_limit = replacement;
+      }
+    }
+  }
+
+  true
+}
+
+// Mirror of `cancel_order` for pegged orders.
+fn cancel_peg_order(ob: &mut OrderBook, order_id: OrderIdx) -> bool {
+  let order = match ob.peg_orders.try_free(order_id) {
+    Some(order) => order,
+    None => return false,
+  };
+
+  let level_id = order.parent_limit.unwrap();
+  let is_buy = order.is_buy;
+
+  ob.events.push(Event::Out { order_id: order_id as u64, kind: OrderKind::Peg });
+
+  match order.prev_order {
+    Some(prev_id) => {
+      ob.peg_orders.get_mut(prev_id).next_order = order.next_order;
+    }
+    None => {
+      ob.peg_levels.get_mut(level_id).head_order = order.next_order;
+    }
+  }
+  match order.next_order {
+    Some(next_id) => {
+      ob.peg_orders.get_mut(next_id).prev_order = order.prev_order;
+    }
+    None => {
+      ob.peg_levels.get_mut(level_id).tail_order = order.prev_order;
+    }
+  }
+
+  let level = ob.peg_levels.get_mut(level_id);
+  level.order_count -= 1;
+  level.size -= order.shares;
+
+  if level.order_count == 0 {
+    let is_best = if is_buy {
+      ob.best_peg_buy_level == Some(level_id)
+    } else {
+      ob.best_peg_sell_level == Some(level_id)
+    };
+    let replacement = if is_best {
+      if is_buy { find_peg_predecessor(ob, level_id) } else { find_peg_successor(ob, level_id) }
+    } else {
+      None
+    };
+
+    remove_peg_level(ob, level_id, is_buy);
+
+    if is_best {
+      if is_buy {
+        ob.best_peg_buy_level = replacement;
+      } else {
+        ob.best_peg_sell_level = replacement;
+      }
+    }
+  }
+
+  true
+}
+
+// Unlinks a now-empty Limit from the price tree and the lookup table,
+// handling the three standard BST-delete cases (leaf, one child, two
+// children via the in-order predecessor).
+fn remove_limit(ob: &mut OrderBook, limit_id: LimitIdx, is_buy: bool) {
+  let limit = ob.limits.free(limit_id);
+  limits_lookup_mut(ob, is_buy)[limit.limit_price as usize] = None;
+
+  match (limit.left_child, limit.right_child) {
+    (None, None) => {
+      replace_child(ob, limit.parent, limit_id, None, is_buy);
+    }
+    (Some(child_id), None) | (None, Some(child_id)) => {
+      ob.limits.get_mut(child_id).parent = limit.parent;
+      replace_child(ob, limit.parent, limit_id, Some(child_id), is_buy);
+    }
+    (Some(left_id), Some(right_id)) => {
+      // in-order predecessor: rightmost node of the left subtree
+      let mut pred_id = left_id;
+      while let Some(next_id) = ob.limits.get(pred_id).right_child {
+        pred_id = next_id;
+      }
+      let pred_parent = ob.limits.get(pred_id).parent;
+      let pred_left = ob.limits.get(pred_id).left_child;
+
+      if pred_id != left_id {
+        // detach pred from its parent's right slot, promoting pred's left child
+        replace_child(ob, pred_parent, pred_id, pred_left, is_buy);
+        if let Some(pl_id) = pred_left {
+          ob.limits.get_mut(pl_id).parent = pred_parent;
+        }
+        ob.limits.get_mut(pred_id).left_child = Some(left_id);
+        ob.limits.get_mut(left_id).parent = Some(pred_id);
+      }
+
+      ob.limits.get_mut(pred_id).right_child = Some(right_id);
+      ob.limits.get_mut(right_id).parent = Some(pred_id);
+
+      ob.limits.get_mut(pred_id).parent = limit.parent;
+      replace_child(ob, limit.parent, limit_id, Some(pred_id), is_buy);
+    }
+  }
+}
+
+fn replace_child(ob: &mut OrderBook, parent: Option<LimitIdx>, old_child: LimitIdx, new_child: Option<LimitIdx>, is_buy: bool) {
+  match parent {
+    None => {
+      if is_buy {
+        ob.buy_tree_root = new_child;
+      } else {
+        ob.sell_tree_root = new_child;
+      }
+    }
+    Some(parent_id) => {
+      let parent_limit = ob.limits.get_mut(parent_id);
+      if parent_limit.left_child == Some(old_child) {
+        parent_limit.left_child = new_child;
+      } else {
+        parent_limit.right_child = new_child;
+      }
+    }
+  }
+}
+
+// Mirror of `remove_limit` for the peg-offset tree.
+fn remove_peg_level(ob: &mut OrderBook, level_id: LimitIdx, is_buy: bool) {
+  let level = ob.peg_levels.free(level_id);
+
+  match (level.left_child, level.right_child) {
+    (None, None) => {
+      replace_peg_child(ob, level.parent, level_id, None, is_buy);
+    }
+    (Some(child_id), None) | (None, Some(child_id)) => {
+      ob.peg_levels.get_mut(child_id).parent = level.parent;
+      replace_peg_child(ob, level.parent, level_id, Some(child_id), is_buy);
+    }
+    (Some(left_id), Some(right_id)) => {
+      let mut pred_id = left_id;
+      while let Some(next_id) = ob.peg_levels.get(pred_id).right_child {
+        pred_id = next_id;
+      }
+      let pred_parent = ob.peg_levels.get(pred_id).parent;
+      let pred_left = ob.peg_levels.get(pred_id).left_child;
+
+      if pred_id != left_id {
+        replace_peg_child(ob, pred_parent, pred_id, pred_left, is_buy);
+        if let Some(pl_id) = pred_left {
+          ob.peg_levels.get_mut(pl_id).parent = pred_parent;
+        }
+        ob.peg_levels.get_mut(pred_id).left_child = Some(left_id);
+        ob.peg_levels.get_mut(left_id).parent = Some(pred_id);
+      }
+
+      ob.peg_levels.get_mut(pred_id).right_child = Some(right_id);
+      ob.peg_levels.get_mut(right_id).parent = Some(pred_id);
+
+      ob.peg_levels.get_mut(pred_id).parent = level.parent;
+      replace_peg_child(ob, level.parent, level_id, Some(pred_id), is_buy);
+    }
+  }
+}
+
+fn replace_peg_child(ob: &mut OrderBook, parent: Option<LimitIdx>, old_child: LimitIdx, new_child: Option<LimitIdx>, is_buy: bool) {
+  match parent {
+    None => {
+      if is_buy {
+        ob.peg_buy_tree_root = new_child;
+      } else {
+        ob.peg_sell_tree_root = new_child;
+      }
+    }
+    Some(parent_id) => {
+      let parent_level = ob.peg_levels.get_mut(parent_id);
+      if parent_level.left_child == Some(old_child) {
+        parent_level.left_child = new_child;
+      } else {
+        parent_level.right_child = new_child;
+      }
+    }
+  }
+}
+
+// Removes the tail (oldest) order of `limit_id`'s FIFO list and fixes up the
+// Limit's bookkeeping and tail/head pointers. Caller must ensure a tail order exists.
+fn pop_tail_order(ob: &mut OrderBook, limit_id: LimitIdx) -> Order {
+  let tail_id = ob.limits.get(limit_id).tail_order.unwrap();
+  let removed = ob.orders.free(tail_id);
+  let prev_id = removed.prev_order;
+
+  let limit = ob.limits.get_mut(limit_id);
+  limit.order_count -= 1;
+  limit.size -= removed.shares;
+  limit.total_vol -= removed.shares * limit.limit_price;
+  limit.tail_order = prev_id;
+
+  match prev_id {
+    Some(prev_order_id) => {
+      ob.orders.get_mut(prev_order_id).next_order = None;
+    }
+    None => {
+      ob.limits.get_mut(limit_id).head_order = None;
+    }
+  }
+
+  ob.events.push(Event::Out { order_id: tail_id as u64, kind: OrderKind::Fixed });
+
+  removed
+}
+
+// Mirror of `pop_tail_order` for a peg level.
+fn pop_peg_tail_order(ob: &mut OrderBook, level_id: LimitIdx) -> PegOrder {
+  let tail_id = ob.peg_levels.get(level_id).tail_order.unwrap();
+  let removed = ob.peg_orders.free(tail_id);
+  let prev_id = removed.prev_order;
+
+  let level = ob.peg_levels.get_mut(level_id);
+  level.order_count -= 1;
+  level.size -= removed.shares;
+  level.tail_order = prev_id;
+
+  match prev_id {
+    Some(prev_order_id) => {
+      ob.peg_orders.get_mut(prev_order_id).next_order = None;
+    }
+    None => {
+      ob.peg_levels.get_mut(level_id).head_order = None;
+    }
+  }
+
+  ob.events.push(Event::Out { order_id: tail_id as u64, kind: OrderKind::Peg });
+
+  removed
+}
+
+// Consumes orders from the tail (oldest first) of `limit_id`'s FIFO list
+// until `shares_to_execute` is exhausted or the list runs dry. Fully consumed
+// orders are removed from the order arena; the last one may be left resting
+// with a reduced `shares`. A resting order owned by `taker_owner_id` is
+// handled per `stp_behavior` instead of being traded against. Every actual
+// trade is recorded as a `Fill` event. Returns (shares still unmatched,
+// whether the Limit is now empty and should be evicted from the tree).
+fn drain_limit(
+  ob: &mut OrderBook,
+  limit_id: LimitIdx,
+  taker_order_id: u64,
+  mut shares_to_execute: u64,
+  taker_owner_id: u64,
+  stp_behavior: SelfTradeBehavior,
+) -> Result<(u64, bool), OrderBookError> {
+  while shares_to_execute > 0 {
+    let tail_id = match ob.limits.get(limit_id).tail_order {
+      Some(id) => id,
+      None => break,
+    };
+
+    let tail_order = ob.orders.get(tail_id);
+    let tail_shares = tail_order.shares;
+    let tail_is_buy = tail_order.is_buy;
+
+    if tail_order.owner_id == taker_owner_id {
+      match stp_behavior {
+        SelfTradeBehavior::AbortTransaction => return Err(OrderBookError::SelfTradeAborted),
+        SelfTradeBehavior::CancelProvide => {
+          pop_tail_order(ob, limit_id);
+        }
+        SelfTradeBehavior::DecrementTake => {
+          let overlap = tail_shares.min(shares_to_execute);
+          shares_to_execute -= overlap;
+          if overlap == tail_shares {
+            pop_tail_order(ob, limit_id);
+          } else {
+            let limit = ob.limits.get_mut(limit_id);
+            limit.size -= overlap;
+            limit.total_vol -= overlap * limit.limit_price;
+            ob.orders.get_mut(tail_id).shares -= overlap;
+          }
+        }
+      }
+      continue;
+    }
+
+    if tail_shares > shares_to_execute {
+      let limit = ob.limits.get_mut(limit_id);
+      let price = limit.limit_price;
+      limit.size -= shares_to_execute;
+      limit.total_vol -= shares_to_execute * price;
+      ob.orders.get_mut(tail_id).shares -= shares_to_execute;
+      ob.events.push(Event::Fill {
+        maker_order_id: tail_id as u64,
+        maker_kind: OrderKind::Fixed,
+        taker_order_id,
+        price,
+        shares: shares_to_execute,
+        maker_side: tail_is_buy,
+      });
+      shares_to_execute = 0;
+      break;
+    }
+
+    let price = ob.limits.get(limit_id).limit_price;
+    shares_to_execute -= tail_shares;
+    ob.events.push(Event::Fill {
+      maker_order_id: tail_id as u64,
+      maker_kind: OrderKind::Fixed,
+      taker_order_id,
+      price,
+      shares: tail_shares,
+      maker_side: tail_is_buy,
+    });
+    pop_tail_order(ob, limit_id);
+  }
+
+  let should_delete = ob.limits.get(limit_id).order_count == 0;
+  Ok((shares_to_execute, should_delete))
+}
+
+// Mirror of `drain_limit` for a peg level, matched at `price` (the level's
+// current effective price, already validated eligible by the caller). Each
+// resting peg order's own cap is rechecked as it reaches the tail: once one
+// is temporarily invalid (its own `peg_limit` is violated at the current
+// oracle price, even though the order the level was selected by was not)
+// draining stops, leaving it and everything behind it resting —
+// we never skip over the oldest order to reach a younger one.
+fn drain_peg_level(
+  ob: &mut OrderBook,
+  level_id: LimitIdx,
+  taker_order_id: u64,
+  mut shares_to_execute: u64,
+  taker_owner_id: u64,
+  stp_behavior: SelfTradeBehavior,
+  price: u64,
+) -> Result<(u64, bool), OrderBookError> {
+  while shares_to_execute > 0 {
+    let tail_id = match ob.peg_levels.get(level_id).tail_order {
+      Some(id) => id,
+      None => break,
+    };
+
+    let tail_order = ob.peg_orders.get(tail_id);
+    if peg_effective_price(ob.oracle_price, tail_order.peg_offset, tail_order.peg_limit, tail_order.is_buy).is_none() {
+      break;
+    }
+    let tail_shares = tail_order.shares;
+    let tail_is_buy = tail_order.is_buy;
+
+    if tail_order.owner_id == taker_owner_id {
+      match stp_behavior {
+        SelfTradeBehavior::AbortTransaction => return Err(OrderBookError::SelfTradeAborted),
+        SelfTradeBehavior::CancelProvide => {
+          pop_peg_tail_order(ob, level_id);
+        }
+        SelfTradeBehavior::DecrementTake => {
+          let overlap = tail_shares.min(shares_to_execute);
+          shares_to_execute -= overlap;
+          if overlap == tail_shares {
+            pop_peg_tail_order(ob, level_id);
+          } else {
+            ob.peg_levels.get_mut(level_id).size -= overlap;
+            ob.peg_orders.get_mut(tail_id).shares -= overlap;
+          }
+        }
+      }
+      continue;
+    }
+
+    if tail_shares > shares_to_execute {
+      ob.peg_levels.get_mut(level_id).size -= shares_to_execute;
+      ob.peg_orders.get_mut(tail_id).shares -= shares_to_execute;
+      ob.events.push(Event::Fill {
+        maker_order_id: tail_id as u64,
+        maker_kind: OrderKind::Peg,
+        taker_order_id,
+        price,
+        shares: shares_to_execute,
+        maker_side: tail_is_buy,
+      });
+      shares_to_execute = 0;
+      break;
+    }
+
+    shares_to_execute -= tail_shares;
+    ob.events.push(Event::Fill {
+      maker_order_id: tail_id as u64,
+      maker_kind: OrderKind::Peg,
+      taker_order_id,
+      price,
+      shares: tail_shares,
+      maker_side: tail_is_buy,
+    });
+    pop_peg_tail_order(ob, level_id);
+  }
+
+  let should_delete = ob.peg_levels.get(level_id).order_count == 0;
+  Ok((shares_to_execute, should_delete))
+}
+
+// Read-only walk of the sell side, tail-first per level, checking whether any
+// order within the prospective fill is owned by `owner_id`. Interleaves the
+// fixed and peg trees the same way `execute_buy_order` matches, so it sees
+// exactly the orders a real fill would touch (and none it wouldn't).
+fn would_self_trade_against_asks(ob: &OrderBook, owner_id: u64, mut shares_to_check: u64, expected_price: u64) -> bool {
+  let mut fixed_cur = ob.best_sell_limit;
+  let mut peg_cur = best_eligible_peg_sell(ob);
+
+  while shares_to_check > 0 {
+    let fixed_candidate = fixed_cur.map(|id| (id, ob.limits.get(id).limit_price));
+
+    let from_peg = match (fixed_candidate, peg_cur) {
+      (None, None) => break,
+      (Some(_), None) => false,
+      (None, Some(_)) => true,
+      (Some((_, fixed_price)), Some((_, peg_price))) => peg_price < fixed_price,
+    };
+
+    let price = if from_peg { peg_cur.unwrap().1 } else { fixed_candidate.unwrap().1 };
+    if price > expected_price {
+      break;
+    }
+
+    if from_peg {
+      let level_id = peg_cur.unwrap().0;
+      let mut order_id_opt = ob.peg_levels.get(level_id).tail_order;
+      while let Some(order_id) = order_id_opt {
+        if shares_to_check == 0 {
+          break;
+        }
+        let order = ob.peg_orders.get(order_id);
+        if peg_effective_price(ob.oracle_price, order.peg_offset, order.peg_limit, order.is_buy).is_none() {
+          break;
+        }
+        if order.owner_id == owner_id {
+          return true;
+        }
+        shares_to_check -= order.shares.min(shares_to_check);
+        order_id_opt = order.prev_order;
+      }
+      peg_cur = next_eligible_peg_sell(ob, find_peg_successor(ob, level_id));
+    } else {
+      let limit_id = fixed_candidate.unwrap().0;
+      let mut order_id_opt = ob.limits.get(limit_id).tail_order;
+      while let Some(order_id) = order_id_opt {
+        if shares_to_check == 0 {
+          break;
+        }
+        let order = ob.orders.get(order_id);
+        if order.owner_id == owner_id {
+          return true;
+        }
+        shares_to_check -= order.shares.min(shares_to_check);
+        order_id_opt = order.prev_order;
+      }
+      fixed_cur = find_successor(ob, limit_id);
+    }
+  }
+  false
+}
+
+// Mirror of `would_self_trade_against_asks` for the buy side.
+fn would_self_trade_against_bids(ob: &OrderBook, owner_id: u64, mut shares_to_check: u64, expected_price: u64) -> bool {
+  let mut fixed_cur = ob.best_buy_limit;
+  let mut peg_cur = best_eligible_peg_buy(ob);
+
+  while shares_to_check > 0 {
+    let fixed_candidate = fixed_cur.map(|id| (id, ob.limits.get(id).limit_price));
+
+    let from_peg = match (fixed_candidate, peg_cur) {
+      (None, None) => break,
+      (Some(_), None) => false,
+      (None, Some(_)) => true,
+      (Some((_, fixed_price)), Some((_, peg_price))) => peg_price > fixed_price,
+    };
+
+    let price = if from_peg { peg_cur.unwrap().1 } else { fixed_candidate.unwrap().1 };
+    if price < expected_price {
+      break;
+    }
+
+    if from_peg {
+      let level_id = peg_cur.unwrap().0;
+      let mut order_id_opt = ob.peg_levels.get(level_id).tail_order;
+      while let Some(order_id) = order_id_opt {
+        if shares_to_check == 0 {
+          break;
+        }
+        let order = ob.peg_orders.get(order_id);
+        if peg_effective_price(ob.oracle_price, order.peg_offset, order.peg_limit, order.is_buy).is_none() {
+          break;
+        }
+        if order.owner_id == owner_id {
+          return true;
+        }
+        shares_to_check -= order.shares.min(shares_to_check);
+        order_id_opt = order.prev_order;
+      }
+      peg_cur = next_eligible_peg_buy(ob, find_peg_predecessor(ob, level_id));
+    } else {
+      let limit_id = fixed_candidate.unwrap().0;
+      let mut order_id_opt = ob.limits.get(limit_id).tail_order;
+      while let Some(order_id) = order_id_opt {
+        if shares_to_check == 0 {
+          break;
+        }
+        let order = ob.orders.get(order_id);
+        if order.owner_id == owner_id {
+          return true;
+        }
+        shares_to_check -= order.shares.min(shares_to_check);
+        order_id_opt = order.prev_order;
+      }
+      fixed_cur = find_predecessor(ob, limit_id);
+    }
+  }
+  false
+}
+
+// Cheapest currently-marketable sell peg level at or after `start`, walking
+// successors past any level whose tail order's cap is violated at the
+// current oracle price.
+fn next_eligible_peg_sell(ob: &OrderBook, mut cur: Option<LimitIdx>) -> Option<(LimitIdx, u64)> {
+  while let Some(level_id) = cur {
+    let tail_id = ob.peg_levels.get(level_id).tail_order.unwrap();
+    let tail = ob.peg_orders.get(tail_id);
+    if let Some(price) = peg_effective_price(ob.oracle_price, tail.peg_offset, tail.peg_limit, tail.is_buy) {
+      return Some((level_id, price));
+    }
+    cur = find_peg_successor(ob, level_id);
+  }
+  None
+}
+
+// Mirror of `next_eligible_peg_sell` for the buy side.
+fn next_eligible_peg_buy(ob: &OrderBook, mut cur: Option<LimitIdx>) -> Option<(LimitIdx, u64)> {
+  while let Some(level_id) = cur {
+    let tail_id = ob.peg_levels.get(level_id).tail_order.unwrap();
+    let tail = ob.peg_orders.get(tail_id);
+    if let Some(price) = peg_effective_price(ob.oracle_price, tail.peg_offset, tail.peg_limit, tail.is_buy) {
+      return Some((level_id, price));
+    }
+    cur = find_peg_predecessor(ob, level_id);
+  }
+  None
+}
+
+fn best_eligible_peg_sell(ob: &OrderBook) -> Option<(LimitIdx, u64)> {
+  next_eligible_peg_sell(ob, ob.best_peg_sell_level)
+}
+
+fn best_eligible_peg_buy(ob: &OrderBook) -> Option<(LimitIdx, u64)> {
+  next_eligible_peg_buy(ob, ob.best_peg_buy_level)
+}
+
+fn execute_buy_order(
+  ob: &mut OrderBook,
+  taker_order_id: u64,
+  taker_owner_id: u64,
+  mut shares_to_execute: u64,
+  expected_price: u64,
+  stp_behavior: SelfTradeBehavior,
+) -> Result<(), OrderBookError> {
+  validate_order(ob, shares_to_execute, expected_price)?;
+
+  if stp_behavior == SelfTradeBehavior::AbortTransaction
+    && would_self_trade_against_asks(ob, taker_owner_id, shares_to_execute, expected_price)
+  {
+    return Err(OrderBookError::SelfTradeAborted);
+  }
+
+  while shares_to_execute > 0 {
+    let fixed_candidate = ob.best_sell_limit.map(|id| (id, ob.limits.get(id).limit_price));
+    let peg_candidate = best_eligible_peg_sell(ob);
+
+    // Always take whichever side quotes the lower ask; ties favor the fixed
+    // limit, which has been resting at that price for longer.
+    let from_peg = match (fixed_candidate, peg_candidate) {
+      (None, None) => break,
+      (Some(_), None) => false,
+      (None, Some(_)) => true,
+      (Some((_, fixed_price)), Some((_, peg_price))) => peg_price < fixed_price,
+    };
+
+    let best_price = if from_peg { peg_candidate.unwrap().1 } else { fixed_candidate.unwrap().1 };
+    if best_price > expected_price {
+      break;
+    }
+
+    if from_peg {
+      let best_id = peg_candidate.unwrap().0;
+      let (remaining, should_delete) =
+        drain_peg_level(ob, best_id, taker_order_id, shares_to_execute, taker_owner_id, stp_behavior, best_price)?;
+      shares_to_execute = remaining;
+
+      if should_delete {
+        // `best_id` may be a successor of `best_peg_sell_level` reached by
+        // skipping temporarily-invalid levels; only advance the cached
+        // pointer when it was `best_id` itself, so a still-resting skipped
+        // level in between stays reachable once it becomes eligible again.
+        let was_cached_best = ob.best_peg_sell_level == Some(best_id);
+        let successor = find_peg_successor(ob, best_id);
+        remove_peg_level(ob, best_id, false);
+        if was_cached_best {
+          ob.best_peg_sell_level = successor;
+        }
+      }
+    } else {
+      let best_id = fixed_candidate.unwrap().0;
+      let (remaining, should_delete) = drain_limit(ob, best_id, taker_order_id, shares_to_execute, taker_owner_id, stp_behavior)?;
+      shares_to_execute = remaining;
+
+      if should_delete {
+        let successor = find_successor(ob, best_id);
+        remove_limit(ob, best_id, false);
+        ob.best_sell_limit = successor;
+      }
+    }
+  }
+
+  if shares_to_execute > 0 {
+    rest_order(ob, NewOrder { owner_id: taker_owner_id, is_buy: true, shares: shares_to_execute, limit: expected_price });
+  }
+
+  Ok(())
+}
+
+fn execute_sell_order(
+  ob: &mut OrderBook,
+  taker_order_id: u64,
+  taker_owner_id: u64,
+  mut shares_to_execute: u64,
+  expected_price: u64,
+  stp_behavior: SelfTradeBehavior,
+) -> Result<(), OrderBookError> {
+  validate_order(ob, shares_to_execute, expected_price)?;
+
+  if stp_behavior == SelfTradeBehavior::AbortTransaction
+    && would_self_trade_against_bids(ob, taker_owner_id, shares_to_execute, expected_price)
+  {
+    return Err(OrderBookError::SelfTradeAborted);
+  }
+
+  while shares_to_execute > 0 {
+    let fixed_candidate = ob.best_buy_limit.map(|id| (id, ob.limits.get(id).limit_price));
+    let peg_candidate = best_eligible_peg_buy(ob);
+
+    // Always take whichever side quotes the higher bid; ties favor the fixed
+    // limit, which has been resting at that price for longer.
+    let from_peg = match (fixed_candidate, peg_candidate) {
+      (None, None) => break,
+      (Some(_), None) => false,
+      (None, Some(_)) => true,
+      (Some((_, fixed_price)), Some((_, peg_price))) => peg_price > fixed_price,
+    };
+
+    let best_price = if from_peg { peg_candidate.unwrap().1 } else { fixed_candidate.unwrap().1 };
+    if best_price < expected_price {
+      break;
+    }
+
+    if from_peg {
+      let best_id = peg_candidate.unwrap().0;
+      let (remaining, should_delete) =
+        drain_peg_level(ob, best_id, taker_order_id, shares_to_execute, taker_owner_id, stp_behavior, best_price)?;
+      shares_to_execute = remaining;
+
+      if should_delete {
+        // See the mirrored comment in `execute_buy_order`: only advance the
+        // cached pointer when `best_id` was the cached pointer itself.
+        let was_cached_best = ob.best_peg_buy_level == Some(best_id);
+        let predecessor = find_peg_predecessor(ob, best_id);
+        remove_peg_level(ob, best_id, true);
+        if was_cached_best {
+          ob.best_peg_buy_level = predecessor;
+        }
+      }
+    } else {
+      let best_id = fixed_candidate.unwrap().0;
+      let (remaining, should_delete) = drain_limit(ob, best_id, taker_order_id, shares_to_execute, taker_owner_id, stp_behavior)?;
+      shares_to_execute = remaining;
+
+      if should_delete {
+        let predecessor = find_predecessor(ob, best_id);
+        remove_limit(ob, best_id, true);
+        ob.best_buy_limit = predecessor;
+      }
+    }
+  }
+
+  if shares_to_execute > 0 {
+    rest_order(ob, NewOrder { owner_id: taker_owner_id, is_buy: false, shares: shares_to_execute, limit: expected_price });
+  }
+
+  Ok(())
 }
 
 /* ---------------------------------------------------------------------- */
 /* ------------------------ test helpers -------------------------------- */
 /* ---------------------------------------------------------------------- */
 
-fn format_order(o: &Order) -> String {
+fn format_order(order_id: OrderIdx, o: &Order) -> String {
   let typ = if o.is_buy { "buy" } else { "sell" };
-  return format!("<Order {}: {} {} shares at limit {}>", o.order_id, typ, o.shares, o.limit);
+  return format!("<Order {}: {} {} shares at limit {}>", order_id, typ, o.shares, o.limit);
 }
 
-fn print_orders(ob: &OrderBook, head: Option<u64>, tail: Option<u64>) {
+fn print_orders(ob: &OrderBook, head: Option<OrderIdx>, tail: Option<OrderIdx>) {
   let mut res = String::from("[");
   if head == None {
     let tail_id = tail.unwrap();
-    let tail_order = ob.orders_ownership_map.get(&tail_id).unwrap();
-    res.push_str(&format_order(tail_order));
+    let tail_order = ob.orders.get(tail_id);
+    res.push_str(&format_order(tail_id, tail_order));
     res.push_str("]");
     println!("{}", res);
     return
   }
-  let mut head_p = head.clone();
-  while head_p != None {
-    let head_id = head_p.unwrap();
-    let head_order = ob.orders_ownership_map.get(&head_id).unwrap();
-    res.push_str(&format_order(head_order));
+  let mut head_p = head;
+  while let Some(head_id) = head_p {
+    let head_order = ob.orders.get(head_id);
+    res.push_str(&format_order(head_id, head_order));
     head_p = head_order.next_order;
   }
   res.push_str("]");
@@ -128,30 +1425,225 @@ fn print_orders(ob: &OrderBook, head: Option<u64>, tail: Option<u64>) {
 
 fn print_prices_orders(ob: &OrderBook) {
   println!("prices and orders:");
-  for (price, limit_id) in &ob.limits_lookup_map {
-    let limit = ob.limits_ownership_map.get(&limit_id).unwrap();
-    print_orders(ob, limit.head_order, limit.tail_order);
+  for (_price, limit_id) in ob.buy_limits_lookup.iter().chain(ob.sell_limits_lookup.iter()).enumerate() {
+    if let Some(limit_id) = limit_id {
+      let limit = ob.limits.get(*limit_id);
+      print_orders(ob, limit.head_order, limit.tail_order);
+    }
   }
 }
 
 fn main() {
-  // u64 => Order
-  let orders_ownership_map: HashMap<u64, Order> = HashMap::new();
-  // u64 => Limit
-  let limits_ownership_map: HashMap<u64, Limit> = HashMap::new();
+  let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
 
-  // limit_price => limit_id
-  let limits_lookup_map: HashMap<u64, u64> = HashMap::new();
+  add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 10, limit: 500 }).unwrap();
+  add_order(&mut ob, NewOrder { owner_id: 2, is_buy: false, shares: 20, limit: 400 }).unwrap();
 
-  let mut ob = OrderBook {
-    orders_ownership_map, limits_ownership_map, limits_lookup_map
-  };
+  print_prices_orders(&ob);
+}
 
-  let order_1 = Order { order_id: 1, is_buy: true, shares: 10, limit: 500, next_order: None, prev_order: None, parent_limit: None };
-  let order_2 = Order { order_id: 2, is_buy: false, shares: 20, limit: 400, next_order: None, prev_order: None, parent_limit: None };
+#[cfg(test)]
+mod tests {
+  use super::*;
 
-  add_order(&mut ob, order_1);
-  add_order(&mut ob, order_2);
+  #[test]
+  fn same_price_bid_and_ask_rest_in_separate_limits() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: false, shares: 5, limit: 100 }).unwrap(); // ask
+    add_order(&mut ob, NewOrder { owner_id: 2, is_buy: true, shares: 5, limit: 100 }).unwrap(); // bid, does not cross (resting ask is ignored by add_order)
 
-  print_prices_orders(&ob);
+    let ask_limit_id = ob.sell_limits_lookup[100].unwrap();
+    let bid_limit_id = ob.buy_limits_lookup[100].unwrap();
+    assert_ne!(ask_limit_id, bid_limit_id);
+
+    let ask_limit = ob.limits.get(ask_limit_id);
+    assert_eq!(ask_limit.order_count, 1);
+    assert_eq!(ob.orders.get(ask_limit.tail_order.unwrap()).owner_id, 1);
+
+    let bid_limit = ob.limits.get(bid_limit_id);
+    assert_eq!(bid_limit.order_count, 1);
+    assert_eq!(ob.orders.get(bid_limit.tail_order.unwrap()).owner_id, 2);
+
+    assert_eq!(ob.sell_tree_root, Some(ask_limit_id));
+    assert_eq!(ob.buy_tree_root, Some(bid_limit_id));
+  }
+
+  #[test]
+  fn cancel_handles_two_children_bst_delete() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
+    // Buy-side tree: 100 is root, 50/150 its children, 25/75 under 50, 40 under 25 --
+    // cancelling the order resting at 50 forces the two-children delete case, whose
+    // in-order predecessor (40) is reached through 25 rather than being 25 itself.
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 1, limit: 100 }).unwrap(); // order 0
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 1, limit: 50 }).unwrap(); // order 1
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 1, limit: 150 }).unwrap(); // order 2
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 1, limit: 25 }).unwrap(); // order 3
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 1, limit: 75 }).unwrap(); // order 4
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 1, limit: 40 }).unwrap(); // order 5
+
+    let best_before = ob.best_buy_limit;
+
+    assert!(cancel_order(&mut ob, 1));
+
+    assert!(ob.buy_limits_lookup[50].is_none());
+    let limit_100 = ob.buy_limits_lookup[100].unwrap();
+    let limit_40 = ob.buy_limits_lookup[40].unwrap();
+    let limit_25 = ob.buy_limits_lookup[25].unwrap();
+    let limit_75 = ob.buy_limits_lookup[75].unwrap();
+
+    assert_eq!(ob.limits.get(limit_100).left_child, Some(limit_40));
+    assert_eq!(ob.limits.get(limit_40).parent, Some(limit_100));
+    assert_eq!(ob.limits.get(limit_40).left_child, Some(limit_25));
+    assert_eq!(ob.limits.get(limit_40).right_child, Some(limit_75));
+    assert_eq!(ob.limits.get(limit_25).right_child, None);
+    assert_eq!(ob.limits.get(limit_25).parent, Some(limit_40));
+    assert_eq!(ob.limits.get(limit_75).parent, Some(limit_40));
+    // a mid-tree delete must not disturb the cached best pointer
+    assert_eq!(ob.best_buy_limit, best_before);
+  }
+
+  #[test]
+  fn cancel_handles_leaf_delete() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 1, limit: 100 }).unwrap(); // order 0
+
+    assert!(cancel_order(&mut ob, 0));
+
+    assert!(ob.buy_limits_lookup[100].is_none());
+    assert_eq!(ob.buy_tree_root, None);
+    assert_eq!(ob.best_buy_limit, None);
+  }
+
+  #[test]
+  fn cancel_handles_single_child_delete() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
+    // 100 is root with a single left child at 50; cancelling 100 must promote 50
+    // in place of doing an in-order-predecessor swap (there's no right child).
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 1, limit: 100 }).unwrap(); // order 0
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: true, shares: 1, limit: 50 }).unwrap(); // order 1
+
+    assert!(cancel_order(&mut ob, 0));
+
+    assert!(ob.buy_limits_lookup[100].is_none());
+    let limit_50 = ob.buy_limits_lookup[50].unwrap();
+    assert_eq!(ob.buy_tree_root, Some(limit_50));
+    assert_eq!(ob.limits.get(limit_50).parent, None);
+    assert_eq!(ob.best_buy_limit, Some(limit_50));
+  }
+
+  #[test]
+  fn self_trade_decrement_take_shrinks_both_sides_without_a_fill() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: false, shares: 10, limit: 100 }).unwrap();
+
+    execute_buy_order(&mut ob, 99, 1, 10, 100, SelfTradeBehavior::DecrementTake).unwrap();
+
+    let events = ob.drain_events();
+    assert!(!events.iter().any(|e| matches!(e, Event::Fill { .. })));
+    assert!(ob.sell_limits_lookup[100].is_none());
+    assert_eq!(ob.best_sell_limit, None);
+  }
+
+  #[test]
+  fn self_trade_cancel_provide_removes_resting_order_and_taker_rests() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: false, shares: 10, limit: 100 }).unwrap();
+
+    execute_buy_order(&mut ob, 99, 1, 10, 100, SelfTradeBehavior::CancelProvide).unwrap();
+
+    let events = ob.drain_events();
+    assert!(!events.iter().any(|e| matches!(e, Event::Fill { .. })));
+    assert!(events.iter().any(|e| matches!(e, Event::Out { .. })));
+
+    // nothing traded, so the taker's full size rests as a new buy order
+    let limit_id = ob.buy_limits_lookup[100].unwrap();
+    let limit = ob.limits.get(limit_id);
+    assert_eq!(limit.order_count, 1);
+    let resting = ob.orders.get(limit.tail_order.unwrap());
+    assert_eq!(resting.owner_id, 1);
+    assert!(resting.is_buy);
+    assert_eq!(resting.shares, 10);
+  }
+
+  #[test]
+  fn self_trade_abort_transaction_leaves_the_book_untouched() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: false, shares: 10, limit: 100 }).unwrap();
+
+    let result = execute_buy_order(&mut ob, 99, 1, 10, 100, SelfTradeBehavior::AbortTransaction);
+
+    assert!(matches!(result, Err(OrderBookError::SelfTradeAborted)));
+    assert!(ob.drain_events().is_empty());
+    let limit_id = ob.sell_limits_lookup[100].unwrap();
+    let limit = ob.limits.get(limit_id);
+    assert_eq!(limit.order_count, 1);
+    let resting = ob.orders.get(limit.tail_order.unwrap());
+    assert_eq!(resting.owner_id, 1);
+    assert!(!resting.is_buy);
+    assert_eq!(resting.shares, 10);
+  }
+
+  #[test]
+  fn partial_fill_remainder_rests_even_below_min_size() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 5);
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: false, shares: 7, limit: 100 }).unwrap();
+
+    execute_buy_order(&mut ob, 99, 2, 10, 100, SelfTradeBehavior::CancelProvide).unwrap();
+
+    let events = ob.drain_events();
+    assert!(events.iter().any(|e| matches!(e, Event::Fill { shares: 7, .. })));
+
+    // the 3-share remainder is below min_size(5) but must still rest rather than error
+    let limit_id = ob.buy_limits_lookup[100].unwrap();
+    let limit = ob.limits.get(limit_id);
+    assert_eq!(limit.order_count, 1);
+    let resting = ob.orders.get(limit.tail_order.unwrap());
+    assert_eq!(resting.shares, 3);
+    assert!(resting.is_buy);
+    assert_eq!(resting.owner_id, 2);
+  }
+
+  #[test]
+  fn peg_order_matches_when_its_effective_price_beats_the_fixed_book() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
+    ob.set_oracle_price(100);
+    // effective sell price = 100 + (-10) = 90, above its floor of 0 -> eligible at 90
+    add_peg_order(&mut ob, NewPegOrder { owner_id: 1, is_buy: false, shares: 5, peg_offset: -10, peg_limit: 0 }).unwrap();
+    add_order(&mut ob, NewOrder { owner_id: 2, is_buy: false, shares: 5, limit: 95 }).unwrap();
+
+    execute_buy_order(&mut ob, 99, 3, 5, 100, SelfTradeBehavior::CancelProvide).unwrap();
+
+    let fill_price = ob.drain_events().into_iter().find_map(|e| match e {
+      Event::Fill { price, .. } => Some(price),
+      _ => None,
+    });
+    assert_eq!(fill_price, Some(90));
+
+    // the cheaper peg order traded; the fixed order is still resting untouched
+    assert!(ob.peg_sell_tree_root.is_none());
+    let fixed_limit = ob.limits.get(ob.sell_limits_lookup[95].unwrap());
+    assert_eq!(fixed_limit.order_count, 1);
+  }
+
+  #[test]
+  fn ties_between_peg_and_fixed_prefer_the_fixed_order() {
+    let mut ob = OrderBook::new(1_000, 16, 1, 1, 1);
+    ob.set_oracle_price(100);
+    add_order(&mut ob, NewOrder { owner_id: 1, is_buy: false, shares: 5, limit: 95 }).unwrap();
+    // effective sell price = 100 + (-5) = 95, the same as the fixed order above
+    add_peg_order(&mut ob, NewPegOrder { owner_id: 2, is_buy: false, shares: 5, peg_offset: -5, peg_limit: 0 }).unwrap();
+
+    execute_buy_order(&mut ob, 99, 3, 5, 100, SelfTradeBehavior::CancelProvide).unwrap();
+
+    let fill_price = ob.drain_events().into_iter().find_map(|e| match e {
+      Event::Fill { price, .. } => Some(price),
+      _ => None,
+    });
+    assert_eq!(fill_price, Some(95));
+
+    // the longer-resting fixed order wins the tie; the peg order is untouched
+    assert!(ob.sell_limits_lookup[95].is_none());
+    let peg_level_id = ob.peg_sell_tree_root.unwrap();
+    assert_eq!(ob.peg_levels.get(peg_level_id).order_count, 1);
+  }
 }